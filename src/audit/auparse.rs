@@ -0,0 +1,406 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead};
+use std::time::SystemTime;
+
+use super::{
+    syscall_extract_fields, AuditRecord, ExecveRecord, MessageParseError, Parser, SyscallRecord,
+    SyscallArch,
+};
+
+/// Parses auditd's human-readable log format (the one `ausearch`/`auparse`
+/// read), as opposed to `BinParser`'s raw binary records.
+///
+/// Each audit event is one or more lines sharing the same `msg=audit(ts:id)`
+/// header; we see them one line at a time and stitch the pieces together
+/// by `id`.
+pub struct AuParser<R: BufRead> {
+    reader: R,
+    // EXECVE records whose argc didn't match the aN fields we'd seen
+    // yet, keyed by id; held until either a continuation EXECVE line
+    // for the same id fills in the rest, or a PROCTITLE line shows up
+    // to patch the gaps. An entry that gets neither is never emitted.
+    incomplete_execves: HashMap<u64, PendingExecve>,
+    // Bytes read for the current line when the reader last paused with
+    // WouldBlock before a newline showed up (e.g. a `TailParser` under
+    // an epoll reactor); resumed rather than discarded on the next call
+    // so a line split across reactor wakeups isn't lost.
+    partial_line: String,
+}
+
+impl<R: BufRead> AuParser<R> {
+    pub fn new(reader: R) -> AuParser<R> {
+        AuParser {
+            reader,
+            incomplete_execves: HashMap::new(),
+            partial_line: String::new(),
+        }
+    }
+
+    fn next_line(&mut self) -> Result<String, MessageParseError> {
+        let mut line = std::mem::take(&mut self.partial_line);
+        let n = match self.reader.read_line(&mut line) {
+            Ok(n) => n,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                self.partial_line = line;
+                return Err(MessageParseError::WouldBlock);
+            }
+            Err(err) => return Err(MessageParseError::IoError(err)),
+        };
+        if n == 0 {
+            return Err(MessageParseError::Eof);
+        }
+        while line.ends_with('\n') || line.ends_with('\r') {
+            line.pop();
+        }
+        Ok(line)
+    }
+}
+
+impl<R: BufRead> Parser for AuParser<R> {
+    fn read_event(&mut self) -> Result<AuditRecord, MessageParseError> {
+        loop {
+            let line = self.next_line()?;
+            if line.is_empty() {
+                continue;
+            }
+
+            let record_type = match parse_record_type(&line) {
+                Some(t) => t,
+                None => continue,
+            };
+            let id = parse_id(&line)?;
+
+            match record_type {
+                "SYSCALL" => return Ok(AuditRecord::Syscall(parse_syscall(&line, id)?)),
+                "EXECVE" => {
+                    let parsed = parse_execve_line(&line)?;
+                    let pending = self
+                        .incomplete_execves
+                        .entry(id)
+                        .or_insert_with(|| PendingExecve::new(parsed.timestamp, parsed.timestamp_frac));
+                    pending.merge(parsed);
+
+                    if pending.is_complete() {
+                        let pending = self.incomplete_execves.remove(&id).unwrap();
+                        return Ok(AuditRecord::Execve(pending.into_record(id)));
+                    }
+                }
+                "PROCTITLE" => {
+                    if let Some(pending) = self.incomplete_execves.remove(&id) {
+                        let mut execve = pending.into_record(id);
+                        if let Some(("proctitle", value)) = tokenize(&line).into_iter().find(|&(k, _)| k == "proctitle") {
+                            let argv = decode_proctitle(value);
+                            if !argv.is_empty() {
+                                execve.args = argv;
+                            }
+                        }
+                        return Ok(AuditRecord::Execve(execve));
+                    }
+                    // No EXECVE on this id was waiting on us; nothing to do.
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+fn parse_record_type(line: &str) -> Option<&str> {
+    line.split_whitespace().next()?.strip_prefix("type=")
+}
+
+fn parse_id(line: &str) -> Result<u64, MessageParseError> {
+    let header_start = line.find("msg=audit(")
+        .ok_or_else(|| MessageParseError::MalformedLine(line.to_owned()))?
+        + "msg=audit(".len();
+    let rest = &line[header_start..];
+    let colon = rest.find(':').ok_or_else(|| MessageParseError::MalformedLine(line.to_owned()))?;
+    let close = rest.find(')').ok_or_else(|| MessageParseError::MalformedLine(line.to_owned()))?;
+    let id_str = &rest[colon + 1..close];
+    id_str.parse::<u64>().map_err(|_| MessageParseError::InvalidId(id_str.to_owned()))
+}
+
+fn parse_timestamp(line: &str) -> Result<(i64, i64), MessageParseError> {
+    let header_start = line.find("msg=audit(")
+        .ok_or_else(|| MessageParseError::MalformedLine(line.to_owned()))?
+        + "msg=audit(".len();
+    let rest = &line[header_start..];
+    let colon = rest.find(':').ok_or_else(|| MessageParseError::MalformedLine(line.to_owned()))?;
+    let ts_str = &rest[..colon];
+    let mut parts = ts_str.splitn(2, '.');
+    let secs_str = parts.next().unwrap_or("");
+    let frac_str = parts.next().unwrap_or("0");
+
+    let secs = secs_str.parse::<i64>().map_err(|_| MessageParseError::InvalidTimestamp(secs_str.to_owned()))?;
+    let frac_digits = frac_str.parse::<i64>().map_err(|_| MessageParseError::InvalidTimestampFraction(frac_str.to_owned()))?;
+    // auditd prints milliseconds (3 digits); scale up to nanoseconds to
+    // match the convention SnitchTimestamp otherwise uses.
+    let scale = 10i64.pow(9u32.saturating_sub(frac_str.len() as u32));
+    Ok((secs, frac_digits * scale))
+}
+
+// Splits a line into its space-separated key=value fields. auditd never
+// puts a literal space inside a value (that's exactly what the hex
+// encoding in the EXECVE path exists to avoid), so this is safe even
+// though some values are quoted strings.
+fn tokenize(line: &str) -> Vec<(&str, &str)> {
+    line.split_whitespace()
+        .filter_map(|tok| {
+            let mut parts = tok.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => Some((key, value)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn parse_syscall(line: &str, id: u64) -> Result<SyscallRecord, MessageParseError> {
+    let (timestamp, timestamp_frac) = parse_timestamp(line)?;
+
+    let mut rec = SyscallRecord {
+        id,
+        timestamp,
+        timestamp_frac,
+        inserted_timestamp: SystemTime::now(),
+        arch: SyscallArch::Unknown,
+        syscall: -1,
+        success: false,
+        exit: -1,
+        pid: -1,
+        ppid: -1,
+        uid: -1,
+        gid: -1,
+        auid: -1,
+        euid: -1,
+        egid: -1,
+        suid: -1,
+        sgid: -1,
+        fsuid: -1,
+        fsgid: -1,
+        tty: None,
+        comm: None,
+        exe: None,
+        key: None,
+        subj: None,
+    };
+
+    for (key, value) in tokenize(line) {
+        syscall_extract_fields(&mut rec, key, value);
+    }
+
+    Ok(rec)
+}
+
+// The aN/argc fields parsed off a single EXECVE line. auditd splits a
+// long EXECVE across several lines sharing one msg id, each carrying a
+// subset of the aN fields (and, in practice, a repeated argc); this is
+// the per-line piece that `PendingExecve` accumulates across calls.
+struct ParsedExecveLine {
+    timestamp: i64,
+    timestamp_frac: i64,
+    argc: Option<usize>,
+    indexed_args: Vec<(usize, String)>,
+}
+
+fn parse_execve_line(line: &str) -> Result<ParsedExecveLine, MessageParseError> {
+    let (timestamp, timestamp_frac) = parse_timestamp(line)?;
+
+    let mut argc = None;
+    let mut indexed_args: Vec<(usize, String)> = Vec::new();
+
+    for (key, value) in tokenize(line) {
+        if key == "argc" {
+            argc = Some(value.parse::<usize>().map_err(|_| MessageParseError::InvalidArgc(value.to_owned()))?);
+        } else if let Some(index_str) = key.strip_prefix('a') {
+            if let Ok(index) = index_str.parse::<usize>() {
+                indexed_args.push((index, decode_execve_value(value)));
+            }
+        }
+    }
+
+    Ok(ParsedExecveLine { timestamp, timestamp_frac, argc, indexed_args })
+}
+
+// An EXECVE record being assembled from one or more lines sharing the
+// same msg id. Args are keyed by their `aN` index rather than
+// concatenated in line-arrival order, since a continuation line's
+// indices don't necessarily pick up where the previous one left off.
+struct PendingExecve {
+    timestamp: i64,
+    timestamp_frac: i64,
+    inserted_timestamp: SystemTime,
+    argc: usize,
+    args: BTreeMap<usize, String>,
+}
+
+impl PendingExecve {
+    fn new(timestamp: i64, timestamp_frac: i64) -> PendingExecve {
+        PendingExecve {
+            timestamp,
+            timestamp_frac,
+            inserted_timestamp: SystemTime::now(),
+            argc: 0,
+            args: BTreeMap::new(),
+        }
+    }
+
+    fn merge(&mut self, parsed: ParsedExecveLine) {
+        // Every line of a split EXECVE repeats argc, so the latest one
+        // we've seen is as good as any.
+        if let Some(argc) = parsed.argc {
+            self.argc = argc;
+        }
+        for (index, value) in parsed.indexed_args {
+            self.args.insert(index, value);
+        }
+    }
+
+    // True once we've collected as many args as argc claims there are.
+    // A line with no argc field at all (shouldn't happen, but auditd's
+    // format isn't ours to validate) leaves argc at 0, which counts as
+    // complete straight away.
+    fn is_complete(&self) -> bool {
+        self.args.len() >= self.argc
+    }
+
+    fn into_record(self, id: u64) -> ExecveRecord {
+        ExecveRecord {
+            id,
+            timestamp: self.timestamp,
+            timestamp_frac: self.timestamp_frac,
+            inserted_timestamp: self.inserted_timestamp,
+            args: self.args.into_iter().map(|(_, value)| value).collect(),
+        }
+    }
+}
+
+// Decodes a single EXECVE argument value per the auditd convention:
+// quoted values are passed through literally, unquoted ones that look
+// like an even-length run of hex digits are hex-decoded, and anything
+// else (e.g. "(null)") is kept as-is.
+fn decode_execve_value(raw: &str) -> String {
+    if raw.len() >= 2 && raw.starts_with('"') && raw.ends_with('"') {
+        return raw[1..raw.len() - 1].to_owned();
+    }
+    if is_hex_encoded(raw) {
+        return decode_hex_lossy(raw);
+    }
+    raw.to_owned()
+}
+
+// Decodes a PROCTITLE payload, a single hex-encoded blob of NUL-separated
+// argv entries, into the reconstructed command line.
+fn decode_proctitle(raw: &str) -> Vec<String> {
+    if !is_hex_encoded(raw) {
+        return Vec::new();
+    }
+    let bytes = hex_decode(raw);
+    bytes
+        .split(|&b| b == 0)
+        .filter(|chunk| !chunk.is_empty())
+        .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+        .collect()
+}
+
+fn is_hex_encoded(value: &str) -> bool {
+    !value.is_empty() && value.len() % 2 == 0 && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn hex_decode(value: &str) -> Vec<u8> {
+    value
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let s = std::str::from_utf8(pair).unwrap_or("00");
+            u8::from_str_radix(s, 16).unwrap_or(b'?')
+        })
+        .collect()
+}
+
+fn decode_hex_lossy(value: &str) -> String {
+    String::from_utf8_lossy(&hex_decode(value)).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_quoted_args_literally() {
+        assert_eq!(decode_execve_value("\"-la\""), "-la");
+        assert_eq!(decode_execve_value("\"hello world\""), "hello world");
+    }
+
+    #[test]
+    fn decodes_hex_encoded_args() {
+        // auditd would normally quote "-la" (it has no whitespace); hex
+        // encoding only kicks in for values auditd can't safely quote,
+        // so this just exercises the hex path with a short value.
+        assert_eq!(decode_execve_value("2D6C61"), "-la");
+    }
+
+    #[test]
+    fn leaves_non_hex_unquoted_values_alone() {
+        assert_eq!(decode_execve_value("(null)"), "(null)");
+    }
+
+    #[test]
+    fn decodes_nul_delimited_proctitle() {
+        // "ls\0-la\0" hex-encoded.
+        let hex = "6C7300" .to_owned() + "2D6C6100";
+        assert_eq!(decode_proctitle(&hex), vec!["ls".to_owned(), "-la".to_owned()]);
+    }
+
+    #[test]
+    fn non_hex_proctitle_yields_no_args() {
+        assert!(decode_proctitle("not hex").is_empty());
+    }
+
+    #[test]
+    fn parses_execve_line_with_mixed_quoted_and_hex_args() {
+        let line = r#"type=EXECVE msg=audit(1623792000.123:456): argc=3 a0="ls" a1=2D6C61 a2="/tmp""#;
+        let event = AuParser::new(io::Cursor::new(line.as_bytes())).read_event().unwrap();
+        match event {
+            AuditRecord::Execve(execve) => {
+                assert_eq!(execve.args, vec!["ls".to_owned(), "-la".to_owned(), "/tmp".to_owned()]);
+            }
+            _ => panic!("expected an Execve record"),
+        }
+    }
+
+    #[test]
+    fn merges_execve_continuation_lines_sharing_an_id() {
+        // auditd splits a long argv across several EXECVE lines that
+        // share one msg id; none of them alone has all `argc` args.
+        let log = "type=EXECVE msg=audit(1623792000.123:456): argc=4 a0=\"ls\" a1=\"-la\"\n\
+                    type=EXECVE msg=audit(1623792000.123:456): argc=4 a2=\"/tmp\" a3=\"/var\"\n";
+        let mut parser = AuParser::new(io::Cursor::new(log.as_bytes()));
+        let event = parser.read_event().unwrap();
+        match event {
+            AuditRecord::Execve(execve) => {
+                assert_eq!(execve.args, vec!["ls".to_owned(), "-la".to_owned(), "/tmp".to_owned(), "/var".to_owned()]);
+            }
+            _ => panic!("expected an Execve record"),
+        }
+    }
+
+    #[test]
+    fn falls_back_to_proctitle_when_execve_is_genuinely_truncated() {
+        // Only 1 of argc=3 args ever shows up on an EXECVE line; the
+        // real argv has to come from PROCTITLE instead.
+        let proctitle_hex = "6C7300".to_owned() + "2D6C6100" + "2F746D7000";
+        let log = format!(
+            "type=EXECVE msg=audit(1623792000.123:789): argc=3 a0=\"ls\"\n\
+             type=PROCTITLE msg=audit(1623792000.123:789): proctitle={}\n",
+            proctitle_hex
+        );
+        let mut parser = AuParser::new(io::Cursor::new(log.into_bytes()));
+        let event = parser.read_event().unwrap();
+        match event {
+            AuditRecord::Execve(execve) => {
+                assert_eq!(execve.args, vec!["ls".to_owned(), "-la".to_owned(), "/tmp".to_owned()]);
+            }
+            _ => panic!("expected an Execve record"),
+        }
+    }
+}
@@ -11,9 +11,15 @@ use self::protos::{SnitchTimestamp, ProgramRun, KeepAlive, SnitchReport};
 mod protos;
 mod aubin;
 mod auparse;
+mod tail;
+mod reactor;
+mod scope;
 
 pub use self::aubin::BinParser;
 pub use self::auparse::AuParser;
+pub use self::tail::TailParser;
+pub use self::reactor::{Congestion, EventLoop};
+pub use self::scope::ProcessTreeScope;
 
 // From linux/audit.h
 const AUDIT_SYSCALL: u32 = 1300;
@@ -110,6 +116,11 @@ pub enum MessageParseError {
     InvalidVersion(u32),
     IoError(io::Error),
     Eof,
+    // The underlying reader has nothing available right now (e.g. a
+    // `TailParser` under an external epoll reactor) but isn't at EOF;
+    // callers should stop for this cycle and retry once the reactor
+    // says the input fd is readable again.
+    WouldBlock,
 }
 
 impl MessageParseError {
@@ -124,6 +135,7 @@ impl MessageParseError {
             &MessageParseError::InvalidVersion(ref badver) => format!("Unsupported audit version: {}", badver),
             &MessageParseError::IoError(ref ioerr) => ioerr.description().to_owned(),
             &MessageParseError::Eof => String::from("EOF"),
+            &MessageParseError::WouldBlock => String::from("No data available yet"),
         }
     }
 }
@@ -162,7 +174,27 @@ fn write_pb_and_flush<T: Message>(cos: &mut CodedOutputStream, msg: &T) -> io::R
     return Ok(());
 }
 
-pub fn dispatch_keepalive<T: Write>(stream: &mut T) -> io::Result<()> {
+// Wraps a serialized SnitchReport payload with the message type and the
+// length prefix the collector expects on the wire. Shared by the
+// dispatch_* functions below and by the event-loop reactor, which queues
+// these frames instead of writing them out synchronously.
+fn build_frame<T: Message>(message_type: i32, payload_msg: &T) -> io::Result<Vec<u8>> {
+    let mut msg = SnitchReport::new();
+    msg.set_message_type(message_type);
+    let mut payload = msg.take_payload();
+    write_pb_and_flush(&mut CodedOutputStream::vec(&mut payload), payload_msg)?;
+    msg.set_payload(payload);
+
+    let mut full_message = Vec::new();
+    write_pb_and_flush(&mut CodedOutputStream::vec(&mut full_message), &msg)?;
+
+    let mut frame = Vec::with_capacity(4 + full_message.len());
+    frame.write_u32::<NetworkEndian>(full_message.len() as u32)?;
+    frame.extend_from_slice(&full_message);
+    Ok(frame)
+}
+
+pub(crate) fn build_keepalive_frame() -> io::Result<Vec<u8>> {
     let now = SystemTime::now();
     let now_ts = match now.duration_since(UNIX_EPOCH) {
         Ok(ts) => ts,
@@ -177,19 +209,15 @@ pub fn dispatch_keepalive<T: Write>(stream: &mut T) -> io::Result<()> {
     let mut keepalive = KeepAlive::new();
     keepalive.set_timestamp(ts);
 
-    let mut msg = SnitchReport::new();
-    msg.set_message_type(REPORT_TYPE_KEEPALIVE);
-    let mut payload = msg.take_payload();
-    write_pb_and_flush(&mut CodedOutputStream::vec(&mut payload), &keepalive)?;
-    msg.set_payload(payload);
+    build_frame(REPORT_TYPE_KEEPALIVE, &keepalive)
+}
 
-    let mut full_message = Vec::new();
-    write_pb_and_flush(&mut CodedOutputStream::vec(&mut full_message), &msg)?;
-    stream.write_u32::<NetworkEndian>(full_message.len() as u32)?;
-    stream.write_all(&full_message)
+pub fn dispatch_keepalive<T: Write>(stream: &mut T) -> io::Result<()> {
+    let frame = build_keepalive_frame()?;
+    stream.write_all(&frame)
 }
 
-pub fn dispatch_audit_event<T: Write>(stream: &mut T, syscall: &SyscallRecord, execve: &ExecveRecord) -> io::Result<()> {
+pub(crate) fn build_audit_event_frame(syscall: &SyscallRecord, execve: &ExecveRecord) -> io::Result<Vec<u8>> {
     use self::SyscallArch::*;
 
     // We use the timestamp from the syscall record
@@ -252,18 +280,12 @@ pub fn dispatch_audit_event<T: Write>(stream: &mut T, syscall: &SyscallRecord, e
     }
     progrec.set_args(pr_args);
 
-    let mut msg = SnitchReport::new();
-    msg.set_message_type(REPORT_TYPE_PROGRAMRUN);
-    let mut payload = msg.take_payload();
-    write_pb_and_flush(&mut CodedOutputStream::vec(&mut payload), &progrec)?;
-    msg.set_payload(payload);
-
-    let mut full_message = Vec::new();
-    write_pb_and_flush(&mut CodedOutputStream::vec(&mut full_message), &msg)?;
-    stream.write_u32::<NetworkEndian>(full_message.len() as u32)?;
-    stream.write_all(&full_message)?;
+    build_frame(REPORT_TYPE_PROGRAMRUN, &progrec)
+}
 
-    return Ok(());
+pub fn dispatch_audit_event<T: Write>(stream: &mut T, syscall: &SyscallRecord, execve: &ExecveRecord) -> io::Result<()> {
+    let frame = build_audit_event_frame(syscall, execve)?;
+    stream.write_all(&frame)
 }
 
 pub trait Parser {
@@ -0,0 +1,577 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+use libc;
+
+use super::scope::ProcessTreeScope;
+use super::{
+    build_audit_event_frame, build_keepalive_frame, AuditRecord, ExecveRecord, MessageParseError,
+    Parser, SyscallRecord,
+};
+
+const MAX_EPOLL_EVENTS: usize = 16;
+const DEFAULT_HIGH_WATER_MARK: usize = 1024;
+
+// Cap on how many unmatched Syscall/Execve halves we'll buffer waiting
+// for their other half to show up. An `ExecveRecord` carries no pid, so
+// unlike `ProcessTreeScope::admit` we can't filter it by scope up
+// front: when its matching SYSCALL belongs to a process outside the
+// monitored subtree, `admit` buffers (and never returns) that syscall,
+// so its Execve half would otherwise sit in `pending_execves` forever.
+// Bound both maps the same way `ProcessTreeScope` bounds its own
+// orphan buffer.
+const DEFAULT_PENDING_HIGH_WATER_MARK: usize = 4096;
+
+/// What to do with an outgoing frame when the queue has hit its
+/// high-water-mark and the collector still isn't keeping up. The reactor
+/// doesn't pick a policy itself; it surfaces the condition to `run` and
+/// lets the caller decide.
+pub enum Congestion {
+    /// Drop the oldest queued frame to make room for the new one; a
+    /// frame already partly written is kept and the next one dropped.
+    DropOldest,
+    /// Push the new frame onto the queue anyway and keep going.
+    Block,
+    /// Stop the loop; the caller should tear down the connection.
+    Disconnect,
+}
+
+/// A single-threaded, non-blocking pump for the audit-event pipeline.
+///
+/// Drives the output socket, the parser's input fd, and a `timerfd` armed
+/// at the keepalive interval, all registered on one epoll instance. It
+/// closes only the epoll instance and the timerfd it creates itself; the
+/// socket and input fds stay the caller's to close. This
+/// replaces synchronous `write_all` calls (which stall the whole monitor
+/// behind a slow collector) with a queue that's only drained as the
+/// socket reports writable.
+///
+/// `input_fd` must be a fd that only reports readable when the parser
+/// genuinely has more to give it, and whose reads never block past that
+/// (e.g. `TailParser::as_raw_fd()` alongside a `TailParser`-backed
+/// parser). A plain file fd doesn't work here: epoll always reports
+/// regular files as readable, and a parser whose reads can block would
+/// stall `run` the same way the synchronous writes above did.
+pub struct EventLoop<P: Parser> {
+    epoll_fd: RawFd,
+    socket_fd: RawFd,
+    input_fd: RawFd,
+    timer_fd: RawFd,
+    parser: P,
+    queue: VecDeque<Vec<u8>>,
+    // How many bytes of the front frame in `queue` have already been
+    // written, for resuming a partial write.
+    head_offset: usize,
+    high_water_mark: usize,
+    socket_registered_for_write: bool,
+    // Syscall/execve records are parsed one at a time but a ProgramRun
+    // frame needs both halves; stash whichever half arrived first until
+    // its match for the same audit record id shows up.
+    pending_syscalls: HashMap<u64, SyscallRecord>,
+    pending_syscall_order: VecDeque<u64>,
+    pending_execves: HashMap<u64, ExecveRecord>,
+    pending_execve_order: VecDeque<u64>,
+    pending_high_water_mark: usize,
+    // Set when the caller wants reporting scoped to a single process
+    // tree; its pidfd is registered on the same epoll instance as
+    // everything else.
+    scope: Option<ProcessTreeScope>,
+    // Set once `on_congested` answers `Congestion::Disconnect`; `run`
+    // returns as soon as it sees it.
+    disconnect_requested: bool,
+}
+
+impl<P: Parser> EventLoop<P> {
+    pub fn new(socket_fd: RawFd, input_fd: RawFd, keepalive_interval: Duration, parser: P) -> io::Result<EventLoop<P>> {
+        Self::with_high_water_mark(socket_fd, input_fd, keepalive_interval, parser, DEFAULT_HIGH_WATER_MARK)
+    }
+
+    pub fn with_high_water_mark(
+        socket_fd: RawFd,
+        input_fd: RawFd,
+        keepalive_interval: Duration,
+        parser: P,
+        high_water_mark: usize,
+    ) -> io::Result<EventLoop<P>> {
+        set_nonblocking(socket_fd)?;
+
+        let epoll_fd = epoll_create()?;
+        let timer_fd = make_timerfd(keepalive_interval)?;
+
+        epoll_add(epoll_fd, input_fd, libc::EPOLLIN as u32)?;
+        epoll_add(epoll_fd, timer_fd, libc::EPOLLIN as u32)?;
+        // The socket isn't registered until we actually have something
+        // queued to write; an idle collector shouldn't wake us at all.
+
+        Ok(EventLoop {
+            epoll_fd,
+            socket_fd,
+            input_fd,
+            timer_fd,
+            parser,
+            queue: VecDeque::new(),
+            head_offset: 0,
+            high_water_mark,
+            socket_registered_for_write: false,
+            pending_syscalls: HashMap::new(),
+            pending_syscall_order: VecDeque::new(),
+            pending_execves: HashMap::new(),
+            pending_execve_order: VecDeque::new(),
+            pending_high_water_mark: DEFAULT_PENDING_HIGH_WATER_MARK,
+            scope: None,
+            disconnect_requested: false,
+        })
+    }
+
+    /// Restrict reporting to the descendants of `target_pid`. Must be
+    /// called before `run`; registers the target's pidfd on the same
+    /// epoll instance so the loop shuts down when the target exits.
+    pub fn scope_to_process_tree(&mut self, target_pid: i32) -> io::Result<()> {
+        let scope = ProcessTreeScope::open(target_pid)?;
+        epoll_add(self.epoll_fd, scope.pidfd(), libc::EPOLLIN as u32)?;
+        self.scope = Some(scope);
+        Ok(())
+    }
+
+    pub fn queue_len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn high_water_mark(&self) -> usize {
+        self.high_water_mark
+    }
+
+    /// Drive the loop until `on_congested` asks us to disconnect, a write
+    /// to the collector socket fails, or the scoped target (if any)
+    /// exits, each of which returns `Ok(())`; any other unrecoverable
+    /// I/O error is returned as `Err`. `on_congested` is only consulted
+    /// once the queue is at or past its high-water-mark.
+    pub fn run<F>(&mut self, mut on_congested: F) -> io::Result<()>
+    where
+        F: FnMut(&mut EventLoop<P>) -> Congestion,
+    {
+        loop {
+            let mut events: [libc::epoll_event; MAX_EPOLL_EVENTS] = unsafe { std::mem::zeroed() };
+            let n = unsafe {
+                libc::epoll_wait(self.epoll_fd, events.as_mut_ptr(), MAX_EPOLL_EVENTS as i32, -1)
+            };
+            if n < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+
+            for event in &events[..n as usize] {
+                let fd = event.u64 as RawFd;
+                if fd == self.input_fd {
+                    self.drain_input(&mut on_congested)?;
+                } else if fd == self.timer_fd {
+                    self.drain_timer()?;
+                    self.enqueue(build_keepalive_frame()?, &mut on_congested)?;
+                } else if fd == self.socket_fd {
+                    if let Disposition::Disconnect = self.drain_output()? {
+                        return Ok(());
+                    }
+                } else if Some(fd) == self.scope.as_ref().map(|s| s.pidfd()) {
+                    if self.scope.as_ref().unwrap().target_exited()? {
+                        return Ok(());
+                    }
+                }
+                if self.disconnect_requested {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn drain_input<F>(&mut self, on_congested: &mut F) -> io::Result<()>
+    where
+        F: FnMut(&mut EventLoop<P>) -> Congestion,
+    {
+        loop {
+            if self.disconnect_requested {
+                return Ok(());
+            }
+            match self.parser.read_event() {
+                Ok(AuditRecord::Syscall(rec)) => {
+                    let admitted = match self.scope {
+                        Some(ref mut scope) => scope.admit(rec),
+                        None => vec![rec],
+                    };
+                    for rec in admitted {
+                        if let Some(execve) = self.pending_execves.remove(&rec.id) {
+                            let frame = build_audit_event_frame(&rec, &execve)?;
+                            self.enqueue(frame, on_congested)?;
+                        } else {
+                            self.insert_pending_syscall(rec);
+                        }
+                    }
+                }
+                Ok(AuditRecord::Execve(rec)) => {
+                    if let Some(syscall) = self.pending_syscalls.remove(&rec.id) {
+                        let frame = build_audit_event_frame(&syscall, &rec)?;
+                        self.enqueue(frame, on_congested)?;
+                    } else {
+                        // `rec` carries no pid, so unlike the Syscall
+                        // arm above we can't ask `scope` whether this
+                        // one belongs in scope before buffering it; its
+                        // matching syscall, if out of scope, will never
+                        // surface from `admit` to claim it. Bounded
+                        // insertion keeps that case from growing this
+                        // map forever.
+                        self.insert_pending_execve(rec);
+                    }
+                }
+                // Eof: the underlying stream is genuinely exhausted.
+                // WouldBlock: a TailParser-backed parser has drained
+                // everything available for this wakeup but keeps
+                // following the file; either way, stop and let `run`
+                // go back to `epoll_wait` instead of trying to read one
+                // more event that isn't there yet.
+                Err(MessageParseError::Eof) | Err(MessageParseError::WouldBlock) => return Ok(()),
+                Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err.long_description())),
+            }
+        }
+    }
+
+    fn insert_pending_syscall(&mut self, rec: SyscallRecord) {
+        if self.pending_syscalls.len() >= self.pending_high_water_mark {
+            evict_oldest(&mut self.pending_syscalls, &mut self.pending_syscall_order);
+        }
+        self.pending_syscall_order.push_back(rec.id);
+        self.pending_syscalls.insert(rec.id, rec);
+    }
+
+    fn insert_pending_execve(&mut self, rec: ExecveRecord) {
+        if self.pending_execves.len() >= self.pending_high_water_mark {
+            evict_oldest(&mut self.pending_execves, &mut self.pending_execve_order);
+        }
+        self.pending_execve_order.push_back(rec.id);
+        self.pending_execves.insert(rec.id, rec);
+    }
+
+    fn drain_timer(&mut self) -> io::Result<()> {
+        // timerfd read() returns an 8-byte expiration count; we don't
+        // care how many ticks fired while we were busy, just that it did.
+        let mut buf = [0u8; 8];
+        match unsafe { libc::read(self.timer_fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } {
+            n if n >= 0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    fn enqueue<F>(&mut self, frame: Vec<u8>, on_congested: &mut F) -> io::Result<()>
+    where
+        F: FnMut(&mut EventLoop<P>) -> Congestion,
+    {
+        if self.queue.len() >= self.high_water_mark {
+            match on_congested(self) {
+                Congestion::DropOldest => {
+                    // Dropping a partly written front frame would leave
+                    // the collector with a truncated frame and
+                    // `head_offset` pointing into the wrong one.
+                    if self.head_offset > 0 {
+                        self.queue.remove(1);
+                    } else {
+                        self.queue.pop_front();
+                    }
+                }
+                Congestion::Block => (),
+                Congestion::Disconnect => {
+                    self.disconnect_requested = true;
+                    return Ok(());
+                }
+            }
+        }
+
+        self.queue.push_back(frame);
+        self.register_for_write()?;
+        Ok(())
+    }
+
+    fn register_for_write(&mut self) -> io::Result<()> {
+        if self.socket_registered_for_write {
+            return Ok(());
+        }
+        epoll_add(self.epoll_fd, self.socket_fd, libc::EPOLLOUT as u32)?;
+        self.socket_registered_for_write = true;
+        Ok(())
+    }
+
+    fn unregister_for_write(&mut self) -> io::Result<()> {
+        if !self.socket_registered_for_write {
+            return Ok(());
+        }
+        epoll_del(self.epoll_fd, self.socket_fd)?;
+        self.socket_registered_for_write = false;
+        Ok(())
+    }
+
+    fn drain_output(&mut self) -> io::Result<Disposition> {
+        while let Some(frame) = self.queue.front() {
+            let remaining = &frame[self.head_offset..];
+            let n = unsafe {
+                libc::write(self.socket_fd, remaining.as_ptr() as *const libc::c_void, remaining.len())
+            };
+            if n < 0 {
+                let err = io::Error::last_os_error();
+                match err.kind() {
+                    io::ErrorKind::WouldBlock => return Ok(Disposition::KeepGoing),
+                    io::ErrorKind::Interrupted => continue,
+                    _ => return Ok(Disposition::Disconnect),
+                }
+            }
+
+            self.head_offset += n as usize;
+            if self.head_offset >= frame.len() {
+                self.queue.pop_front();
+                self.head_offset = 0;
+            }
+        }
+
+        self.unregister_for_write()?;
+        Ok(Disposition::KeepGoing)
+    }
+}
+
+impl<P: Parser> Drop for EventLoop<P> {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.timer_fd);
+            libc::close(self.epoll_fd);
+        }
+    }
+}
+
+enum Disposition {
+    KeepGoing,
+    Disconnect,
+}
+
+// Evicts whichever unmatched record was queued longest, skipping ids
+// that were already removed from `map` (by a match) since they were
+// queued rather than trying to keep `order` precisely in sync on every
+// match -- that would mean an O(n) scan on the hot path instead of just
+// this cold one.
+fn evict_oldest<T>(map: &mut HashMap<u64, T>, order: &mut VecDeque<u64>) {
+    while let Some(id) = order.pop_front() {
+        if map.remove(&id).is_some() {
+            return;
+        }
+    }
+}
+
+fn epoll_create() -> io::Result<RawFd> {
+    let fd = unsafe { libc::epoll_create1(0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+fn epoll_add(epoll_fd: RawFd, fd: RawFd, events: u32) -> io::Result<()> {
+    let mut event = libc::epoll_event { events, u64: fd as u64 };
+    let rc = unsafe { libc::epoll_ctl(epoll_fd, libc::EPOLL_CTL_ADD, fd, &mut event) };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn epoll_del(epoll_fd: RawFd, fd: RawFd) -> io::Result<()> {
+    let rc = unsafe { libc::epoll_ctl(epoll_fd, libc::EPOLL_CTL_DEL, fd, std::ptr::null_mut()) };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL, 0) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let rc = unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn make_timerfd(interval: Duration) -> io::Result<RawFd> {
+    let timer_fd = unsafe { libc::timerfd_create(libc::CLOCK_MONOTONIC, libc::TFD_NONBLOCK) };
+    if timer_fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let spec = libc::itimerspec {
+        it_interval: libc::timespec {
+            tv_sec: interval.as_secs() as libc::time_t,
+            tv_nsec: interval.subsec_nanos() as libc::c_long,
+        },
+        it_value: libc::timespec {
+            tv_sec: interval.as_secs() as libc::time_t,
+            tv_nsec: interval.subsec_nanos() as libc::c_long,
+        },
+    };
+    let rc = unsafe { libc::timerfd_settime(timer_fd, 0, &spec, std::ptr::null_mut()) };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(timer_fd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::scope::scope_with;
+    use super::super::SyscallArch;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::time::SystemTime;
+
+    // A `Parser` that replays a fixed script instead of reading real
+    // audit log text, so `drain_input` can be exercised without a real
+    // fd backing it.
+    struct ScriptedParser {
+        events: VecDeque<Result<AuditRecord, MessageParseError>>,
+    }
+
+    impl Parser for ScriptedParser {
+        fn read_event(&mut self) -> Result<AuditRecord, MessageParseError> {
+            self.events.pop_front().unwrap_or(Err(MessageParseError::WouldBlock))
+        }
+    }
+
+    fn syscall(id: u64, pid: i32, ppid: i32) -> SyscallRecord {
+        SyscallRecord {
+            id,
+            timestamp: 0,
+            timestamp_frac: 0,
+            inserted_timestamp: SystemTime::now(),
+            arch: SyscallArch::Unknown,
+            syscall: -1,
+            success: true,
+            exit: 0,
+            pid,
+            ppid,
+            uid: -1,
+            gid: -1,
+            auid: -1,
+            euid: -1,
+            egid: -1,
+            suid: -1,
+            sgid: -1,
+            fsuid: -1,
+            fsgid: -1,
+            tty: None,
+            comm: None,
+            exe: None,
+            key: None,
+            subj: None,
+        }
+    }
+
+    fn execve(id: u64) -> ExecveRecord {
+        ExecveRecord {
+            id,
+            timestamp: 0,
+            timestamp_frac: 0,
+            inserted_timestamp: SystemTime::now(),
+            args: vec!["ls".to_owned()],
+        }
+    }
+
+    // EventLoop::new() needs real fds to register on epoll; a connected
+    // UnixStream pair gives it valid ones without a real collector or
+    // audit log on the other end. `drain_input` itself never touches
+    // `input_fd`'s contents (the script above stands in for the
+    // parser), so only the fd's validity matters here.
+    fn test_event_loop(parser: ScriptedParser) -> EventLoop<ScriptedParser> {
+        let (socket, socket_peer) = UnixStream::pair().unwrap();
+        let (input, input_peer) = UnixStream::pair().unwrap();
+        let event_loop = EventLoop::new(
+            socket.as_raw_fd(),
+            input.as_raw_fd(),
+            Duration::from_secs(60),
+            parser,
+        )
+        .unwrap();
+        // EventLoop never closes these fds (its Drop closes only its own
+        // epoll fd and timerfd); leak the Rust-side handles so the fds
+        // stay open for as long as the loop uses them.
+        std::mem::forget(socket);
+        std::mem::forget(socket_peer);
+        std::mem::forget(input);
+        std::mem::forget(input_peer);
+        event_loop
+    }
+
+    fn no_congestion(_: &mut EventLoop<ScriptedParser>) -> Congestion {
+        Congestion::Block
+    }
+
+    #[test]
+    fn execve_for_out_of_scope_syscall_is_never_forwarded() {
+        let mut event_loop = test_event_loop(ScriptedParser {
+            events: vec![
+                Ok(AuditRecord::Syscall(syscall(1, 999, 888))),
+                Ok(AuditRecord::Execve(execve(1))),
+            ]
+            .into(),
+        });
+        event_loop.scope = Some(scope_with(100, 16));
+
+        event_loop.drain_input(&mut no_congestion).unwrap();
+
+        assert_eq!(event_loop.queue_len(), 0);
+        assert!(event_loop.pending_syscalls.is_empty());
+        assert_eq!(event_loop.pending_execves.len(), 1);
+    }
+
+    #[test]
+    fn pending_execves_are_bounded_for_out_of_scope_traffic() {
+        let mut event_loop = test_event_loop(ScriptedParser {
+            events: vec![
+                Ok(AuditRecord::Syscall(syscall(1, 901, 888))),
+                Ok(AuditRecord::Execve(execve(1))),
+                Ok(AuditRecord::Syscall(syscall(2, 902, 888))),
+                Ok(AuditRecord::Execve(execve(2))),
+                Ok(AuditRecord::Syscall(syscall(3, 903, 888))),
+                Ok(AuditRecord::Execve(execve(3))),
+            ]
+            .into(),
+        });
+        event_loop.scope = Some(scope_with(100, 16));
+        event_loop.pending_high_water_mark = 2;
+
+        event_loop.drain_input(&mut no_congestion).unwrap();
+
+        assert_eq!(event_loop.queue_len(), 0);
+        assert_eq!(event_loop.pending_execves.len(), 2);
+        let ids: Vec<u64> = event_loop.pending_execve_order.iter().cloned().collect();
+        assert_eq!(ids, vec![2, 3]);
+    }
+
+    #[test]
+    fn admitted_syscall_and_its_execve_still_produce_a_frame() {
+        let mut event_loop = test_event_loop(ScriptedParser {
+            events: vec![
+                Ok(AuditRecord::Syscall(syscall(1, 101, 100))),
+                Ok(AuditRecord::Execve(execve(1))),
+            ]
+            .into(),
+        });
+        event_loop.scope = Some(scope_with(100, 16));
+
+        event_loop.drain_input(&mut no_congestion).unwrap();
+
+        assert_eq!(event_loop.queue_len(), 1);
+        assert!(event_loop.pending_syscalls.is_empty());
+        assert!(event_loop.pending_execves.is_empty());
+    }
+}
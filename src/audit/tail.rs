@@ -0,0 +1,263 @@
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::slice;
+
+use libc;
+
+// inotify_add_watch() mask covering the events we need to follow a log
+// file across normal appends as well as logrotate-style rotation.
+const WATCH_MASK: u32 = (libc::IN_MODIFY | libc::IN_CREATE | libc::IN_MOVED_FROM | libc::IN_MOVE_SELF) as u32;
+
+const EVENT_BUF_SIZE: usize = 4096;
+
+/// A `Read` source that follows an audit log file the way `tail -f` does,
+/// instead of returning EOF once the current contents have been consumed.
+///
+/// It watches the log's parent directory with Linux inotify to learn when
+/// there's more to read, and also knows how to follow the file across
+/// logrotate: when the watched name is re-created or moved away, it
+/// reopens the path from scratch.
+///
+/// `read` never blocks and never signals real EOF; when there's nothing
+/// new yet it returns an `io::ErrorKind::WouldBlock` error instead. This
+/// is meant to run under an external non-blocking reactor (see
+/// `EventLoop`): register `as_raw_fd()` on the reactor's epoll instance
+/// and only call `read`/`read_line` once that fd has reported readable.
+///
+/// Hand this to `BinParser`/`AuParser` as their input stream (it
+/// implements `Read`, same as any other stream; `AuParser` takes it
+/// behind a `BufReader`) to turn a batch parser into a live tail.
+pub struct TailParser {
+    dir: RawFd,
+    inotify_fd: RawFd,
+    watch_fd: RawFd,
+    file: File,
+    path: PathBuf,
+    file_name: OsString,
+    // Bytes read from the inotify fd that didn't add up to a whole
+    // inotify_event the last time we looked; prepended to the next read.
+    pending: Vec<u8>,
+    // Set once a rotation event has been seen for `path`. We don't swap
+    // `file` as soon as this is noticed; a rotator's rename+recreate can
+    // land in the same inotify batch as one last write to the old file,
+    // and swapping immediately would skip straight past those bytes
+    // without ever reading them. Acted on in `read` only once the old
+    // file has been read to exhaustion (a zero-byte read), so nothing
+    // it already held is lost.
+    pending_reopen: bool,
+}
+
+impl TailParser {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<TailParser> {
+        let path = path.as_ref().to_path_buf();
+        let dir_path = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let file_name = match path.file_name() {
+            Some(name) => name.to_owned(),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "log path has no file name")),
+        };
+
+        let inotify_fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK) };
+        if inotify_fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let dir_file = File::open(&dir_path)?;
+        let dir = dir_file.as_raw_fd();
+        // We only need the fd, not the File's own lifetime management;
+        // the kernel keeps the watch alive until we close inotify_fd.
+        mem::forget(dir_file);
+
+        let watch_fd = add_watch(inotify_fd, &dir_path)?;
+
+        let file = File::open(&path)?;
+
+        Ok(TailParser {
+            dir,
+            inotify_fd,
+            watch_fd,
+            file,
+            path,
+            file_name,
+            pending: Vec::new(),
+            pending_reopen: false,
+        })
+    }
+
+    // Attempts the actual reopen once the old file has nothing left to
+    // give. Returns whether a new file was opened; on `ENOENT` -- the
+    // rename half of a rotation (IN_MOVED_FROM) can be observed before
+    // the rotator creates the replacement file -- returns `false` so the
+    // caller keeps retrying instead of erroring the tailer out.
+    fn reopen(&mut self) -> io::Result<bool> {
+        match File::open(&self.path) {
+            Ok(file) => {
+                self.file = file;
+                Ok(true)
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Consume whole `inotify_event` records out of `self.pending`,
+    // flagging a pending reopen across rotation as each relevant one is
+    // seen (the actual swap happens in `read`, once the old file has
+    // been drained). Any trailing partial record is left in
+    // `self.pending` for next time. Never blocks; operates only on
+    // bytes already buffered.
+    fn process_pending(&mut self) -> io::Result<()> {
+        let mut offset = 0;
+        let header_size = mem::size_of::<libc::inotify_event>();
+
+        loop {
+            if self.pending.len() - offset < header_size {
+                break;
+            }
+            let event = unsafe {
+                &*(self.pending[offset..].as_ptr() as *const libc::inotify_event)
+            };
+            let record_len = header_size + event.len as usize;
+            if self.pending.len() - offset < record_len {
+                break;
+            }
+
+            let name = if event.len > 0 {
+                let name_bytes = unsafe {
+                    slice::from_raw_parts(
+                        self.pending[offset + header_size..].as_ptr(),
+                        event.len as usize,
+                    )
+                };
+                // The kernel NUL-pads the name to a multiple of the
+                // record alignment; trim at the first NUL.
+                let end = name_bytes.iter().position(|&b| b == 0).unwrap_or(name_bytes.len());
+                Some(OsString::from(std::ffi::OsStr::from_bytes(&name_bytes[..end])))
+            } else {
+                None
+            };
+
+            let is_our_file = name.as_ref().map(|n| n == &self.file_name).unwrap_or(false);
+            let mask = event.mask as i32;
+
+            if mask & libc::IN_MOVE_SELF != 0 {
+                // The directory itself moved; nothing more we can do
+                // but keep trying the path we were given.
+                self.pending_reopen = true;
+            } else if is_our_file && (mask & (libc::IN_CREATE | libc::IN_MOVED_FROM) != 0) {
+                // logrotate: our file was recreated (new inode) or
+                // the old inode was moved out from under us.
+                self.pending_reopen = true;
+            }
+
+            offset += record_len;
+        }
+
+        self.pending.drain(..offset);
+        Ok(())
+    }
+
+    // Drain whatever inotify events are already queued on the
+    // (non-blocking) inotify fd, without blocking if there are none.
+    fn drain_activity(&mut self) -> io::Result<()> {
+        loop {
+            let mut buf = [0u8; EVENT_BUF_SIZE];
+            let n = read_nonblocking(self.inotify_fd, &mut buf)?;
+            if n == 0 {
+                return Ok(());
+            }
+            self.pending.extend_from_slice(&buf[..n]);
+            self.process_pending()?;
+        }
+    }
+}
+
+impl Read for TailParser {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read(buf)?;
+        if n > 0 {
+            return Ok(n);
+        }
+
+        // Catch up on any rotation/append notification that's already
+        // queued, then give the old file one more chance: a rotator's
+        // rename+recreate can land in the same inotify batch as one
+        // last write to it, so `process_pending` above may only just
+        // now have learned a reopen is due.
+        self.drain_activity()?;
+        let n = self.file.read(buf)?;
+        if n > 0 {
+            return Ok(n);
+        }
+
+        // Only once the old file has confirmed it has nothing left (the
+        // zero-byte read above) do we actually swap to the new one, so
+        // a rotation never skips past bytes the old file still held.
+        if self.pending_reopen && self.reopen()? {
+            self.pending_reopen = false;
+            let n = self.file.read(buf)?;
+            if n > 0 {
+                return Ok(n);
+            }
+        }
+
+        Err(io::Error::new(io::ErrorKind::WouldBlock, "no audit log activity available yet"))
+    }
+}
+
+impl AsRawFd for TailParser {
+    // The fd an external reactor should register for readability; the
+    // file itself isn't suitable (regular files are always "ready" to
+    // epoll, which defeats the purpose). Readability here means inotify
+    // has something queued, which `read` above will drain.
+    fn as_raw_fd(&self) -> RawFd {
+        self.inotify_fd
+    }
+}
+
+impl Drop for TailParser {
+    fn drop(&mut self) {
+        unsafe {
+            libc::inotify_rm_watch(self.inotify_fd, self.watch_fd);
+            libc::close(self.inotify_fd);
+            libc::close(self.dir);
+        }
+    }
+}
+
+fn add_watch(inotify_fd: RawFd, dir_path: &Path) -> io::Result<RawFd> {
+    let mut bytes: Vec<u8> = dir_path.as_os_str().as_bytes().to_vec();
+    bytes.push(0);
+    let watch_fd = unsafe {
+        libc::inotify_add_watch(inotify_fd, bytes.as_ptr() as *const libc::c_char, WATCH_MASK)
+    };
+    if watch_fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(watch_fd)
+}
+
+// A single non-blocking read of the (already O_NONBLOCK) inotify fd;
+// EAGAIN just means nothing's queued right now, which we report as
+// `Ok(0)` rather than an error.
+fn read_nonblocking(fd: RawFd, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if n >= 0 {
+            return Ok(n as usize);
+        }
+        let err = io::Error::last_os_error();
+        match err.kind() {
+            io::ErrorKind::Interrupted => continue,
+            io::ErrorKind::WouldBlock => return Ok(0),
+            _ => return Err(err),
+        }
+    }
+}